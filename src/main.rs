@@ -4,10 +4,16 @@ extern crate csv;
 
 use rand::distributions::{Distribution, WeightedIndex};
 use rand::seq::IteratorRandom;
+use regex::Regex;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fs::File;
+use std::io::Write;
+
+//Maps a context of the preceding N Songs (oldest first) to the Songs that followed it in the
+//listening history, and how many times they occurred
+type ContextModel = HashMap<Vec<Song>, HashMap<Song, f32>>;
 
 //Represents a single Song
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Deserialize)]
@@ -15,23 +21,198 @@ struct Song {
     track: String,
     artist: String,
     album: String,
+    #[serde(default)]
+    path: Option<String>,
+}
+
+//Holds the parsed command line arguments
+struct Args {
+    input_file_path: String,
+    output_file_path: Option<String>,
+    config_file_path: Option<String>,
+    playlist_length: i32,
+    creativity: f32,
+    order: usize,
+    cooldown: usize,
+    seed: Option<String>,
+    features_file_path: Option<String>,
+    similarity_weight: f32,
+    verbose: bool,
+}
+
+//The shape of the --config JSON file: regex patterns matched against a Song's artist, track,
+//and album. A Song matching any blacklist pattern is dropped; if whitelist is non-empty, only
+//Songs matching at least one whitelist pattern are kept
+#[derive(Deserialize, Default)]
+struct Config {
+    #[serde(default)]
+    blacklist: Vec<String>,
+    #[serde(default)]
+    whitelist: Vec<String>,
+}
+
+//Loads and parses the --config JSON file
+fn load_config(config_file_path: &str) -> Result<Config, Box<dyn Error>> {
+    let config_file = File::open(config_file_path)?;
+    let config: Config = serde_json::from_reader(config_file)?;
+    Ok(config)
+}
+
+//Compiles a list of regex pattern strings into Regexes once, up front
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>, Box<dyn Error>> {
+    patterns.iter().map(|p| Ok(Regex::new(p)?)).collect()
+}
+
+//The key a --features JSON file uses to identify a Song: "<artist> - <track>", same convention
+//as --seed
+fn song_key(song: &Song) -> String {
+    format!("{} - {}", song.artist, song.track)
+}
+
+//Holds the (z-score normalized) acoustic feature vectors loaded from --features, plus enough to
+//blend them into transition weights: the similarity lambda and the dataset's mean pairwise
+//distance, used as a neutral stand-in when a Song has no feature vector
+struct SimilarityModel {
+    features: HashMap<String, Vec<f32>>,
+    mean_distance: f32,
+    lambda: f32,
+}
+
+impl SimilarityModel {
+    //Loads the --features JSON file (a map of song key -> feature vector), z-score normalizes
+    //the vectors per-dimension, and precomputes the dataset's mean pairwise distance
+    fn load(features_file_path: &str, lambda: f32) -> Result<SimilarityModel, Box<dyn Error>> {
+        let features_file = File::open(features_file_path)?;
+        let raw_features: HashMap<String, Vec<f32>> = serde_json::from_reader(features_file)?;
+        let features = zscore_normalize(&raw_features)?;
+        let mean_distance = mean_feature_distance(&features);
+        Ok(SimilarityModel {
+            features,
+            mean_distance,
+            lambda,
+        })
+    }
+
+    //Returns the acoustic distance between two Songs, falling back to the dataset's mean
+    //pairwise distance when either Song has no feature vector
+    fn distance(&self, a: &Song, b: &Song) -> f32 {
+        match (self.features.get(&song_key(a)), self.features.get(&song_key(b))) {
+            (Some(a), Some(b)) => euclidean_distance(a, b),
+            _ => self.mean_distance,
+        }
+    }
+}
+
+//Returns the Euclidean (L2) distance between two equal-length feature vectors
+fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x - y).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+//Z-score normalizes every feature vector per-dimension (subtract the dimension's mean, divide by
+//its standard deviation), so dimensions with different units/scales contribute comparably to
+//euclidean_distance. Errors if the feature vectors don't all share the same length
+fn zscore_normalize(features: &HashMap<String, Vec<f32>>) -> Result<HashMap<String, Vec<f32>>, Box<dyn Error>> {
+    let dim = features.values().next().map(|v| v.len()).unwrap_or(0);
+    let n = features.len() as f32;
+    if let Some((key, v)) = features.iter().find(|(_, v)| v.len() != dim) {
+        return Err(format!(
+            "feature vector for {:?} has length {} but expected {} (from another entry)",
+            key,
+            v.len(),
+            dim
+        )
+        .into());
+    }
+    if dim == 0 || n == 0.0 {
+        return Ok(HashMap::new());
+    }
+
+    let mut means = vec![0.0; dim];
+    for v in features.values() {
+        for (i, x) in v.iter().enumerate() {
+            means[i] += x;
+        }
+    }
+    for mean in means.iter_mut() {
+        *mean /= n;
+    }
+
+    let mut stds = vec![0.0; dim];
+    for v in features.values() {
+        for (i, x) in v.iter().enumerate() {
+            stds[i] += (x - means[i]).powi(2);
+        }
+    }
+    for std in stds.iter_mut() {
+        *std = (*std / n).sqrt();
+        if *std == 0.0 {
+            *std = 1.0; //avoid dividing by zero for a constant feature dimension
+        }
+    }
+
+    let normalized = features
+        .iter()
+        .map(|(key, v)| {
+            let normalized: Vec<f32> = v
+                .iter()
+                .enumerate()
+                .map(|(i, x)| (x - means[i]) / stds[i])
+                .collect();
+            (key.clone(), normalized)
+        })
+        .collect();
+    Ok(normalized)
+}
+
+//Returns the mean Euclidean distance between every pair of distinct feature vectors in the
+//dataset, used as a neutral fallback distance for Songs missing a feature vector
+fn mean_feature_distance(features: &HashMap<String, Vec<f32>>) -> f32 {
+    let vectors: Vec<&Vec<f32>> = features.values().collect();
+    if vectors.len() < 2 {
+        return 0.0;
+    }
+
+    let mut total = 0.0;
+    let mut count = 0usize;
+    for i in 0..vectors.len() {
+        for j in (i + 1)..vectors.len() {
+            total += euclidean_distance(vectors[i], vectors[j]);
+            count += 1;
+        }
+    }
+    total / count as f32
 }
 
 //Set up clap and parse command line arguments
-fn parse_args() -> (String, i32, f32, bool) {
+fn parse_args() -> Args {
     let matches = clap_app!(rplaylist =>
         (version: "0.1.0")
         (author: "github.com/e-dm-b")
         (about: "Uses a modified Markov chain to generate a playlist based on Last.fm listening history")
         (@arg INPUT: +required "Sets the input file to use")
+        (@arg OUTPUT: -o --output +takes_value "Writes the generated playlist to FILE as an extended M3U playlist")
         (@arg LENGTH: -l --length +takes_value "Sets the number of songs in the generated playlist")
         (@arg CREATIVITY: -c --creativity +takes_value "Sets the playlist generation creativity")
+        (@arg ORDER: --order +takes_value "Sets the order (number of preceding songs) the Markov chain conditions on")
+        (@arg COOLDOWN: --cooldown +takes_value "Sets how many recently played songs are down-weighted to avoid short repeat loops")
+        (@arg CONFIG: --config +takes_value "Sets a JSON config file of blacklist/whitelist regex patterns to filter songs by")
+        (@arg SEED: --seed +takes_value "Starts the playlist from the song matching \"<artist> - <track>\" instead of a random song")
+        (@arg FEATURES: --features +takes_value "Sets a JSON file of acoustic feature vectors to blend into transition weights")
+        (@arg SIMILARITY_WEIGHT: --("similarity-weight") +takes_value "Sets how strongly --features acoustic similarity biases transitions (lambda)")
         (@arg verbose: -v --verbose ... "Prints verbose information on song probabilities. Useful for fine-tuning creativity")
     )
     .get_matches();
 
     let input_file_path = matches.value_of("INPUT").unwrap();
 
+    let output_file_path = matches.value_of("OUTPUT").map(|s| s.to_string());
+
+    let config_file_path = matches.value_of("CONFIG").map(|s| s.to_string());
+
     let playlist_length = match matches.value_of("LENGTH").unwrap_or("20").parse::<i32>() {
         Ok(playlist_length) => playlist_length,
         Err(_e) => 20, //if playlist_length cannot be parsed set to 20
@@ -42,14 +223,40 @@ fn parse_args() -> (String, i32, f32, bool) {
         Err(_e) => 0.0, //if creativity cannot be parsed set to 0.0
     };
 
+    let order = match matches.value_of("ORDER").unwrap_or("1").parse::<usize>() {
+        Ok(order) if order > 0 => order,
+        _ => 1, //if order cannot be parsed or is 0, fall back to a plain first-order chain
+    };
+
+    let cooldown = match matches.value_of("COOLDOWN").unwrap_or("0").parse::<usize>() {
+        Ok(cooldown) => cooldown,
+        Err(_e) => 0, //if cooldown cannot be parsed set to 0 (disabled)
+    };
+
+    let seed = matches.value_of("SEED").map(|s| s.to_string());
+
+    let features_file_path = matches.value_of("FEATURES").map(|s| s.to_string());
+
+    let similarity_weight = match matches.value_of("SIMILARITY_WEIGHT").unwrap_or("1").parse::<f32>() {
+        Ok(similarity_weight) => similarity_weight,
+        Err(_e) => 1.0, //if similarity_weight cannot be parsed set to 1.0
+    };
+
     let verbosity = matches.is_present("verbose");
 
-    (
-        input_file_path.to_string(),
+    Args {
+        input_file_path: input_file_path.to_string(),
+        output_file_path,
+        config_file_path,
         playlist_length,
         creativity,
-        verbosity,
-    )
+        order,
+        cooldown,
+        seed,
+        features_file_path,
+        similarity_weight,
+        verbose: verbosity,
+    }
 }
 
 //Reads the input file and populates the suppied Vec of Songs, with the first Song being the last in the file
@@ -62,18 +269,29 @@ fn read_songs(input_file: &File, songs_list: &mut Vec<Song>) -> Result<(), Box<d
     Ok(())
 }
 
-//Returns a random Song selected from the list of unique Songs
-fn random_song(uniques: &HashMap<Song, HashMap<Song, f32>>) -> Song {
-    uniques
-        .keys()
-        .into_iter()
+//Returns a random Song selected from the supplied set of Songs (in main: every Song in the
+//listening history that passes the blacklist/whitelist filter)
+fn random_song(all_songs: &HashSet<Song>) -> Song {
+    all_songs
+        .iter()
         .choose(&mut rand::thread_rng())
         .unwrap()
         .clone()
 }
 
-//Selects a Song given a HashMap of Songs and their probabilities
-fn choose_by_prob(probabilities: &HashMap<Song, f32>, verbose: bool) -> Song {
+//Selects a Song given a HashMap of Songs and their probabilities, zeroing out the weight of any
+//candidate currently in the cooldown window so recently played Songs are avoided where possible.
+//If every candidate is in cooldown (or every weight would become zero), falls back to the
+//un-penalized weights so WeightedIndex::new never panics on an all-zero weight vector.
+//When `similarity` is given, weights are first blended towards acoustically similar candidates:
+//w' = count * exp(-lambda * distance(current_song, candidate))
+fn choose_by_prob(
+    probabilities: &HashMap<Song, f32>,
+    current_song: &Song,
+    similarity: Option<&SimilarityModel>,
+    cooldown: &VecDeque<Song>,
+    verbose: bool,
+) -> Song {
     let mut songs: Vec<Song> = Vec::new();
     let mut weights: Vec<f32> = Vec::new();
 
@@ -81,6 +299,36 @@ fn choose_by_prob(probabilities: &HashMap<Song, f32>, verbose: bool) -> Song {
         songs.push(s);
         weights.push(p);
     }
+    //kept as the ultimate fallback: similarity blending and cooldown zeroing can both drive
+    //every weight to 0.0, but the raw counts never can (apply_creativity clamps them to >= 1.0)
+    let raw_weights = weights.clone();
+
+    if let Some(similarity) = similarity {
+        for (song, weight) in songs.iter().zip(weights.iter_mut()) {
+            let distance = similarity.distance(current_song, song);
+            *weight *= (-similarity.lambda * distance).exp();
+        }
+    }
+    let weights = if weights.iter().any(|w| *w > 0.0) {
+        weights
+    } else {
+        raw_weights.clone()
+    };
+
+    let mut cooled_weights = weights.clone();
+    for (i, song) in songs.iter().enumerate() {
+        if cooldown.contains(song) {
+            cooled_weights[i] = 0.0;
+        }
+    }
+    let weights = if cooled_weights.iter().any(|w| *w > 0.0) {
+        cooled_weights
+    } else if weights.iter().any(|w| *w > 0.0) {
+        weights
+    } else {
+        raw_weights
+    };
+
     let distribution = WeightedIndex::new(&weights).unwrap();
 
     if verbose {
@@ -100,26 +348,174 @@ fn choose_by_prob(probabilities: &HashMap<Song, f32>, verbose: bool) -> Song {
         .clone()
 }
 
-//Predicts the next Song given the current Song and a list of all Songs and their potential next songs
+//Returns true if `song` passes the blacklist/whitelist filter: not matched by any blacklist
+//pattern, and - when the whitelist is non-empty - matched by at least one whitelist pattern
+fn song_allowed(song: &Song, blacklist: &[Regex], whitelist: &[Regex]) -> bool {
+    let matches_any = |patterns: &[Regex]| {
+        patterns
+            .iter()
+            .any(|p| p.is_match(&song.artist) || p.is_match(&song.track) || p.is_match(&song.album))
+    };
+
+    if matches_any(blacklist) {
+        return false;
+    }
+    whitelist.is_empty() || matches_any(whitelist)
+}
+
+//Builds the order-N Markov model: a sliding window of `order` consecutive Songs is used as the
+//context key, mapped to the Song that followed it and how many times that happened.
+//Windows touching a Song outside `allowed` are skipped entirely, so a filtered Song is dropped
+//as both a context entry and a successor
+fn build_model(all_songs: &[Song], order: usize, allowed: &HashSet<Song>) -> ContextModel {
+    let mut model: ContextModel = HashMap::new();
+    if all_songs.len() <= order {
+        return model;
+    }
+
+    for i in 0..all_songs.len() - order {
+        let context: Vec<Song> = all_songs[i..i + order].to_vec();
+        let next_song: Song = all_songs.get(i + order).cloned().unwrap();
+
+        if context.iter().any(|s| !allowed.contains(s)) || !allowed.contains(&next_song) {
+            continue;
+        }
+
+        let mut next_songs: HashMap<Song, f32> = HashMap::new(); // create inner HashMap
+        next_songs.insert(next_song.clone(), 0.0);
+
+        let context_map = model.entry(context).or_insert(next_songs);
+
+        *context_map.entry(next_song).or_insert(0.0) += 1.0;
+    }
+    model
+}
+
+//Apply creativity to counts, per context, exactly as for the first-order chain
+//If count is below average for all possible songs, add average * creativity to it
+//If count is above average for all possible songs, subtract average * creativity from it
+fn apply_creativity(model: &mut ContextModel, creativity: f32) {
+    for (_context, following_songs) in model.iter_mut() {
+        let mut row_total: f32 = 0.0;
+        for (_next_song, count) in following_songs.iter() {
+            row_total += *count;
+        }
+        let row_average: f32 = row_total / following_songs.len() as f32;
+
+        for (_next_song, count) in following_songs.iter_mut() {
+            if *count < row_average {
+                *count += row_average * creativity;
+            } else if *count > row_average {
+                *count -= row_average * creativity;
+            }
+            if *count < 1 as f32 {
+                //clamp counts to avoid negatives
+                *count = 1 as f32;
+            }
+        }
+    }
+}
+
+//Predicts the next Song given the current context (the last played Songs, oldest first) and the
+//per-order Markov models (models[k - 1] holds the order-k model). Looks up the full-length
+//context first; if it's unseen or has no recorded successors, backs off by dropping the oldest
+//Song in the context and retrying at the next lower order, down to order 1, then finally falls
+//back to a random Song
 fn predict_next(
-    current_song: &Song,
-    uniques: &HashMap<Song, HashMap<Song, f32>>,
+    context: &[Song],
+    models: &[ContextModel],
+    all_songs: &HashSet<Song>,
+    similarity: Option<&SimilarityModel>,
+    cooldown: &VecDeque<Song>,
     verbose: bool,
 ) -> Song {
-    let next_songs_opt = uniques.get(current_song);
-    if next_songs_opt.is_some() && next_songs_opt.unwrap().len() != 0 {
-        return choose_by_prob(next_songs_opt.unwrap(), verbose);
+    let start_order = context.len().min(models.len());
+    for order in (1..=start_order).rev() {
+        let sub_context = &context[context.len() - order..];
+        let next_songs_opt = models[order - 1].get(sub_context);
+        if next_songs_opt.is_some() && next_songs_opt.unwrap().len() != 0 {
+            let current_song = context.last().unwrap();
+            return choose_by_prob(next_songs_opt.unwrap(), current_song, similarity, cooldown, verbose);
+        }
+    }
+    //No context of any order matched, or matched with no possible next song
+    random_song(all_songs) //So, return a random song instead
+}
+
+//Parses a "<artist> - <track>" --seed string into (artist, track)
+fn parse_seed(seed: &str) -> Option<(String, String)> {
+    let mut parts = seed.splitn(2, " - ");
+    let artist = parts.next()?.trim().to_string();
+    let track = parts.next()?.trim().to_string();
+    Some((artist, track))
+}
+
+//Finds the best-matching Song for a --seed string: an exact artist+track match first, falling
+//back to a case-insensitive substring match on both fields. When several Songs match, prefers
+//whichever has the most total successor occurrences in the order-1 model, i.e. is most "connected"
+fn find_seed_song(
+    seed_artist: &str,
+    seed_track: &str,
+    allowed: &HashSet<Song>,
+    first_order_model: &ContextModel,
+) -> Option<Song> {
+    let successor_count = |song: &Song| -> f32 {
+        first_order_model
+            .get(&vec![song.clone()])
+            .map(|next_songs| next_songs.values().sum())
+            .unwrap_or(0.0)
+    };
+
+    if let Some(song) = allowed
+        .iter()
+        .filter(|s| s.artist == seed_artist && s.track == seed_track)
+        .max_by(|a, b| successor_count(a).partial_cmp(&successor_count(b)).unwrap())
+    {
+        return Some(song.clone());
     }
-    //Couldn't find current_song in uniques, or current_song has no possible next song
-    random_song(uniques) //So, return a random song instead
+
+    let seed_artist = seed_artist.to_lowercase();
+    let seed_track = seed_track.to_lowercase();
+    allowed
+        .iter()
+        .filter(|s| {
+            s.artist.to_lowercase().contains(&seed_artist) && s.track.to_lowercase().contains(&seed_track)
+        })
+        .max_by(|a, b| successor_count(a).partial_cmp(&successor_count(b)).unwrap())
+        .cloned()
+}
+
+//Returns the path/URI to use for a Song in the exported M3U, falling back to a placeholder
+//of the form <artist>/<album>/<track> when the CSV had no path column for this Song
+fn song_location(song: &Song) -> String {
+    song.path
+        .clone()
+        .unwrap_or_else(|| format!("{}/{}/{}", song.artist, song.album, song.track))
+}
+
+//Writes the generated playlist to output_file_path as an extended M3U playlist
+fn write_m3u(output_file_path: &str, playlist: &[Song]) -> Result<(), Box<dyn Error>> {
+    let mut writer = File::create(output_file_path)?;
+    writeln!(writer, "#EXTM3U")?;
+    for song in playlist {
+        writeln!(writer, "#EXTINF:-1,{} - {}", song.artist, song.track)?;
+        writeln!(writer, "{}", song_location(song))?;
+    }
+    Ok(())
 }
 
 fn main() {
-    let (input_file_path, playlist_length, creativity, verbose) = parse_args();
+    let args = parse_args();
+    let input_file_path = args.input_file_path;
+    let playlist_length = args.playlist_length;
+    let creativity = args.creativity;
+    let order = args.order;
+    let cooldown = args.cooldown;
+    let verbose = args.verbose;
 
     if verbose {
-        println!("Using input file {}\nUsing playlist length {}\nUsing creativity {}\n",
-               input_file_path, playlist_length, creativity);
+        println!("Using input file {}\nUsing playlist length {}\nUsing creativity {}\nUsing order {}\n",
+               input_file_path, playlist_length, creativity, order);
     }
 
     //open input file
@@ -138,51 +534,120 @@ fn main() {
         std::process::exit(1);
     }
 
-    //generate HashMap of unique songs and HashMaps of Songs and counts
-    //outer HashMap contains every unique Song as the keys and inner HashMaps as the values
-    //inner HashMaps contain every Song following the key Song, and how many times they occur
-    let mut unique_songs: HashMap<Song, HashMap<Song, f32>> = HashMap::new();
-    for i in 0..all_songs.len() - 1 {
-        let current_song: Song = all_songs.get(i).cloned().unwrap();
-        let next_song: Song = all_songs.get(i + 1).cloned().unwrap();
-
-        let mut next_songs: HashMap<Song, f32> = HashMap::new(); // create inner HashMap
-        next_songs.insert(next_song.clone(), 0.0);
-
-        let current_song_map = unique_songs.entry(current_song).or_insert(next_songs);
+    //load the blacklist/whitelist config, if any, and compile its patterns once
+    let config = match &args.config_file_path {
+        Some(config_file_path) => match load_config(config_file_path) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Failed to load config file {}: {}", config_file_path, err);
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+    let blacklist = match compile_patterns(&config.blacklist) {
+        Ok(blacklist) => blacklist,
+        Err(err) => {
+            println!("Failed to compile blacklist pattern: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let whitelist = match compile_patterns(&config.whitelist) {
+        Ok(whitelist) => whitelist,
+        Err(err) => {
+            println!("Failed to compile whitelist pattern: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-        *current_song_map.entry(next_song).or_insert(0.0) += 1.0;
+    //build a Markov model for every order from 1 up to the requested order, so predict_next
+    //can back off to a lower order when the full-length context is unseen
+    let all_unique_songs: HashSet<Song> = all_songs
+        .iter()
+        .cloned()
+        .filter(|s| song_allowed(s, &blacklist, &whitelist))
+        .collect();
+    if all_unique_songs.is_empty() {
+        println!("No songs left after applying --config blacklist/whitelist filtering");
+        std::process::exit(1);
+    }
+    let mut models: Vec<ContextModel> = Vec::with_capacity(order);
+    for k in 1..=order {
+        let mut model = build_model(&all_songs, k, &all_unique_songs);
+        apply_creativity(&mut model, creativity);
+        models.push(model);
     }
 
-    //Apply creativity to counts
-    //If count is below average for all possible songs, add average * creativity to it
-    //If count is above average for all possible songs, subtract average * creativity from it
-    for (_key_song, following_songs) in unique_songs.iter_mut() {
-        let mut row_total: f32 = 0.0;
-        for (_next_song, count) in following_songs.iter() {
-            row_total += *count;
-        }
-        let row_average: f32 = row_total / following_songs.len() as f32;
-
-        for (_next_song, count) in following_songs.iter_mut() {
-            if *count < row_average {
-                *count += row_average * creativity;
-            } else if *count > row_average {
-                *count -= row_average * creativity;
+    //load the acoustic --features file, if any, normalizing it once up front
+    let similarity = match &args.features_file_path {
+        Some(features_file_path) => match SimilarityModel::load(features_file_path, args.similarity_weight) {
+            Ok(similarity) => Some(similarity),
+            Err(err) => {
+                println!("Failed to load features file {}: {}", features_file_path, err);
+                std::process::exit(1);
             }
-            if *count < 1 as f32 {
-                //clamp counts to avoid negatives
-                *count = 1 as f32;
+        },
+        None => None,
+    };
+
+    //choose a random song, or the user-specified --seed song, to seed playlist generation
+    let mut current_song = match &args.seed {
+        Some(seed) => {
+            let (seed_artist, seed_track) = match parse_seed(seed) {
+                Some(parsed) => parsed,
+                None => {
+                    println!("Invalid --seed {:?}; expected \"<artist> - <track>\"", seed);
+                    std::process::exit(1);
+                }
+            };
+            match find_seed_song(&seed_artist, &seed_track, &all_unique_songs, &models[0]) {
+                Some(song) => song,
+                None => {
+                    println!("No song found matching --seed {:?}", seed);
+                    std::process::exit(1);
+                }
             }
         }
-    }
-
-    //choose a random song, then use it to seed playlist generation
-    let mut current_song = random_song(&unique_songs);
+        None => random_song(&all_unique_songs),
+    };
     println!("1.\t{} - {}", current_song.artist, current_song.track);
+    let mut playlist: Vec<Song> = vec![current_song.clone()];
+    let mut context: VecDeque<Song> = VecDeque::with_capacity(order);
+    context.push_back(current_song.clone());
+    let mut cooldown_window: VecDeque<Song> = VecDeque::with_capacity(cooldown);
+    cooldown_window.push_back(current_song.clone());
+    while cooldown_window.len() > cooldown {
+        cooldown_window.pop_front();
+    }
 
     for i in 2..=playlist_length {
-        current_song = predict_next(&current_song, &unique_songs, verbose);
+        let context_songs: Vec<Song> = context.iter().cloned().collect();
+        current_song = predict_next(
+            &context_songs,
+            &models,
+            &all_unique_songs,
+            similarity.as_ref(),
+            &cooldown_window,
+            verbose,
+        );
         println!("{}.\t{} - {}", i, current_song.artist, current_song.track);
+        playlist.push(current_song.clone());
+
+        context.push_back(current_song.clone());
+        while context.len() > order {
+            context.pop_front();
+        }
+
+        cooldown_window.push_back(current_song.clone());
+        while cooldown_window.len() > cooldown {
+            cooldown_window.pop_front();
+        }
+    }
+
+    if let Some(output_file_path) = args.output_file_path {
+        if let Err(err) = write_m3u(&output_file_path, &playlist) {
+            println!("Failed to write playlist to {}: {}", output_file_path, err);
+            std::process::exit(1);
+        }
     }
 }